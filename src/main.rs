@@ -1,4 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io::{self, BufRead, Write};
 
 struct Flags {
     polymorphic_let: bool,
@@ -18,75 +20,254 @@ impl Flags {
     }
 }
 
+// A half-open byte range into the source text an `Expr` was parsed from.
+// Hand-built trees (such as most of the unit tests below) have nothing real
+// to point at, so they use `Span::DUMMY`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Span {
+    start: usize,
+    end: usize,
+}
+
+impl Span {
+    const DUMMY: Self = Self { start: 0, end: 0 };
+}
+
 // types
 #[derive(Clone, Debug, PartialEq, Eq)]
-enum Error {
+enum ErrorKind<'id> {
     Undefined,
-    ExpectedFn,
-    Unification,
+    ExpectedFn {
+        found: Type<'id>,
+    },
+    Unification {
+        expected: Type<'id>,
+        found: Type<'id>,
+    },
+    InfiniteType,
+    NoInstance {
+        class: Id<'id>,
+        ty: Type<'id>,
+    },
+}
+
+// An inference failure, blaming the `Expr` being visited when it occurred
+// (or, for `unify` called directly on a `Type`, whatever span the caller
+// passes in) so a diagnostic can point at the offending source slice.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Error<'id> {
+    kind: ErrorKind<'id>,
+    span: Span,
+}
+
+impl<'id> Error<'id> {
+    fn new(span: Span, kind: ErrorKind<'id>) -> Self {
+        Self { kind, span }
+    }
+
+    fn message(&self) -> String {
+        match &self.kind {
+            ErrorKind::Undefined => "undefined variable".to_string(),
+            ErrorKind::ExpectedFn { found } => format!("expected a function, found {found}"),
+            ErrorKind::Unification { expected, found } => {
+                format!("expected {expected}, found {found}")
+            }
+            ErrorKind::InfiniteType => "infinite type".to_string(),
+            ErrorKind::NoInstance { class, ty } => format!("no instance {class} {ty}"),
+        }
+    }
+
+    // Renders the offending source slice with a caret underline under the
+    // span plus a one-line message, e.g. `expected Int, found Bool -> Bool`.
+    fn render(&self, source: &str) -> String {
+        render_span(self.span, &self.message(), source)
+    }
+}
+
+// Shared by `Error::render` and `ParseError::render`: prints the source line
+// containing `span`, with a caret underline beneath it, followed by
+// `message`.
+fn render_span(span: Span, message: &str, source: &str) -> String {
+    let Span { start, end } = span;
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..]
+        .find('\n')
+        .map_or(source.len(), |i| start + i);
+    let line = &source[line_start..line_end];
+    let col = start - line_start;
+    let width = end.saturating_sub(start).max(1);
+    format!("{line}\n{}{} {message}", " ".repeat(col), "^".repeat(width))
 }
 
 type Id<'id> = &'id str;
 
+// A node in the expression tree, paired with the span of source it was
+// parsed from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Expr<'id> {
+    kind: ExprKind<'id>,
+    span: Span,
+}
+
+impl<'id> Expr<'id> {
+    fn new(span: Span, kind: ExprKind<'id>) -> Self {
+        Self { kind, span }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ExprKind<'id> {
+    Id(Id<'id>),                                                // x
+    Fn(Id<'id>, Box<Expr<'id>>),                                // x -> x
+    Let(Id<'id>, Vec<Id<'id>>, Box<Expr<'id>>, Box<Expr<'id>>), // let f x y = v; b
+    Call(Box<Expr<'id>>, Box<Expr<'id>>),                       // f x
+    Int(i64),                                                   // 1
+    Bool(bool),                                                 // true
+    When(Box<Expr<'id>>, Vec<(Pattern<'id>, Expr<'id>)>),       // when x is p1 -> e1, p2 -> e2
+    Record(Vec<(Id<'id>, Expr<'id>)>),                          // { a: 1, b: 2 }
+    Field(Box<Expr<'id>>, Id<'id>),                             // e.l
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
-enum Expr<'id> {
-    Id(Id<'id>),                                      // x
-    Fn(Id<'id>, Box<Self>),                           // x -> x
-    Let(Id<'id>, Vec<Id<'id>>, Box<Self>, Box<Self>), // let f x y = v; b
-    Call(Box<Self>, Box<Self>),                       // f x
+enum Pattern<'id> {
+    Int(i64),     // 1
+    Wildcard,     // _
+    Var(Id<'id>), // x
 }
 
 type Var = usize;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
-enum Type {
+enum Type<'id> {
     Var(Var),
     Fn(Box<Self>, Box<Self>),
+    Con(Id<'id>),
+    // A row-polymorphic record: `rest` is the row variable standing for
+    // whatever other fields the record may have, or `None` if it's closed.
+    Record {
+        fields: BTreeMap<Id<'id>, Self>,
+        rest: Option<Var>,
+    },
+}
+
+impl<'id> fmt::Display for Type<'id> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Var(v) => write!(f, "t{v}"),
+            Type::Con(name) => write!(f, "{name}"),
+            Type::Fn(param, body) => match **param {
+                Type::Fn(..) => write!(f, "({param}) -> {body}"),
+                _ => write!(f, "{param} -> {body}"),
+            },
+            Type::Record { fields, rest } => {
+                write!(f, "{{ ")?;
+                for (i, (label, ty)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{label}: {ty}")?;
+                }
+                if let Some(r) = rest {
+                    if !fields.is_empty() {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "..t{r}")?;
+                }
+                write!(f, " }}")
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
-struct Scheme {
+struct Scheme<'id> {
     bounds: Vec<Var>,
-    ty: Type,
+    constraints: Vec<Constraint<'id>>,
+    ty: Type<'id>,
 }
 
-type Unifier = HashMap<Var, Type>;
-
+// A type-class obligation attached to a `Scheme`, e.g. `Num a`: `ty` must
+// belong to `class` once it's resolved to a concrete type.
 #[derive(Clone, Debug, PartialEq, Eq)]
+struct Constraint<'id> {
+    class: Id<'id>,
+    ty: Type<'id>,
+}
+
+type Subst<'id> = HashMap<Var, Type<'id>>;
+
+// A pluggable source of builtin/prelude bindings consulted by `Context::get`
+// once the lexical scopes come up empty, mirroring nac3's symbol-resolver
+// design: callers register polymorphic builtins once (e.g. `add : Int ->
+// Int -> Int`) and get them instantiated fresh at every use site.
+trait SymbolResolver<'id>: fmt::Debug {
+    fn resolve(&self, id: &str) -> Option<Scheme<'id>>;
+}
+
+// A resolver backed by a plain map, suitable for loading a prelude of
+// builtin schemes before running inference.
+#[derive(Clone, Debug, Default)]
+struct MapResolver<'id> {
+    builtins: HashMap<Id<'id>, Scheme<'id>>,
+}
+
+impl<'id> MapResolver<'id> {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&mut self, id: Id<'id>, scheme: Scheme<'id>) {
+        self.builtins.insert(id, scheme);
+    }
+}
+
+impl<'id> SymbolResolver<'id> for MapResolver<'id> {
+    fn resolve(&self, id: &str) -> Option<Scheme<'id>> {
+        self.builtins.get(id).cloned()
+    }
+}
+
+#[derive(Debug)]
 struct Context<'id> {
     vars: usize,
-    scopes: Vec<HashMap<Id<'id>, Scheme>>,
+    scopes: Vec<HashMap<Id<'id>, Scheme<'id>>>,
+    subst: Subst<'id>,
+    resolver: Option<Box<dyn SymbolResolver<'id> + 'id>>,
+    // Known type-class instances, e.g. `("Num", "Int")` for `Num Int`.
+    instances: BTreeSet<(Id<'id>, Id<'id>)>,
+    // Constraints instantiated from a `Scheme` that haven't yet been solved
+    // against a binding's generalization, e.g. the `Num a` obligation
+    // incurred by using a builtin `add` before its caller's `let` finishes.
+    wanted: Vec<Constraint<'id>>,
 }
 
 // functions
 impl<'id> Expr<'id> {
-    fn infer(&self, context: &mut Context<'id>, flags: &Flags) -> Result<Type, Error> {
-        match self {
-            Self::Id(id) => context.get(id).map(|ty| ty.clone()),
-            Self::Let(key, params, value, body) => {
+    fn infer(&self, context: &mut Context<'id>, flags: &Flags) -> Result<Type<'id>, Error<'id>> {
+        match &self.kind {
+            ExprKind::Id(id) => context.get(id, self.span),
+            ExprKind::Int(_) => Ok(Type::Con("Int")),
+            ExprKind::Bool(_) => Ok(Type::Con("Bool")),
+            ExprKind::Let(key, params, value, body) => {
                 context.enter();
                 let scheme = {
-                    let prev_vars = context.vars;
                     context.enter();
-                    let mut bounds = Vec::new();
                     for p in params {
                         let var = context.fresh();
-                        bounds.push(var.clone());
-                        let scheme = Scheme::from(Type::Var(var));
-                        context.insert(p, scheme);
+                        context.insert(p, Scheme::from(Type::Var(var)));
                     }
+                    let wanted_before = context.wanted.len();
                     let ty = value.infer(context, flags)?;
                     context.exit();
-                    let out = Scheme { bounds, ty };
-                    context.vars = prev_vars;
-                    out
+                    let constraints = context.wanted.split_off(wanted_before);
+                    context.generalize(ty, constraints, value.span)?
                 };
                 context.insert(key, scheme);
                 let result = body.infer(context, flags);
                 context.exit();
                 result
             }
-            Self::Fn(x, body) => {
+            ExprKind::Fn(x, body) => {
                 context.enter();
                 let k = context.fresh();
                 context.insert(x, Scheme::from(Type::Var(k)));
@@ -94,62 +275,232 @@ impl<'id> Expr<'id> {
                 context.exit();
                 Ok(Type::Fn(Box::new(Type::Var(k)), Box::new(ty)))
             }
-            Self::Call(f, x) => {
+            ExprKind::Call(f, x) => {
                 let f_ty = f.infer(context, flags)?;
-                if let Type::Fn(param_ty, mut body_ty) = f_ty {
-                    let x_ty = x.infer(context, flags)?;
-                    let mut unifier = HashMap::new();
-                    param_ty.unify(&x_ty, &mut unifier)?;
-                    body_ty.subst(&unifier);
-                    Ok(*body_ty)
-                } else {
-                    Err(Error::ExpectedFn)
+                // Resolve just the top level: a literal `Fn` unifies trivially
+                // below, and a still-unbound `Var` (e.g. a lambda parameter
+                // called as a function) is exactly what the App rule needs to
+                // pin down, by unifying against a fresh `Fn(arg, result)`
+                // rather than requiring `f` to already look like a function.
+                match context.resolve(&f_ty) {
+                    Type::Fn(_, _) | Type::Var(_) => {
+                        let x_ty = x.infer(context, flags)?;
+                        let result = Type::Var(context.fresh());
+                        let expected = Type::Fn(Box::new(x_ty), Box::new(result.clone()));
+                        f_ty.unify(&expected, context, x.span)?;
+                        Ok(context.resolve_deep(&result))
+                    }
+                    found => Err(Error::new(f.span, ErrorKind::ExpectedFn { found })),
                 }
             }
+            ExprKind::When(scrutinee, arms) => {
+                let scrutinee_ty = scrutinee.infer(context, flags)?;
+                let mut result_ty: Option<Type<'id>> = None;
+                for (pattern, body) in arms {
+                    context.enter();
+                    match pattern {
+                        Pattern::Int(_) => {
+                            Type::Con("Int").unify(&scrutinee_ty, context, scrutinee.span)?;
+                        }
+                        Pattern::Wildcard => {}
+                        Pattern::Var(name) => {
+                            let k = context.fresh();
+                            context.insert(name, Scheme::from(Type::Var(k)));
+                            Type::Var(k).unify(&scrutinee_ty, context, scrutinee.span)?;
+                        }
+                    }
+                    let body_ty = body.infer(context, flags);
+                    context.exit();
+                    let body_ty = body_ty?;
+                    match &result_ty {
+                        Some(expected) => expected.unify(&body_ty, context, body.span)?,
+                        None => result_ty = Some(body_ty),
+                    }
+                }
+                let result_ty = result_ty.expect("when must have at least one arm");
+                Ok(context.resolve_deep(&result_ty))
+            }
+            ExprKind::Record(fields) => {
+                let mut ty_fields = BTreeMap::new();
+                for (label, value) in fields {
+                    let ty = value.infer(context, flags)?;
+                    ty_fields.insert(*label, ty);
+                }
+                Ok(Type::Record {
+                    fields: ty_fields,
+                    rest: None,
+                })
+            }
+            ExprKind::Field(record, label) => {
+                let record_ty = record.infer(context, flags)?;
+                let field_var = context.fresh();
+                let row_var = context.fresh();
+                let open = Type::Record {
+                    fields: BTreeMap::from([(*label, Type::Var(field_var))]),
+                    rest: Some(row_var),
+                };
+                record_ty.unify(&open, context, record.span)?;
+                Ok(context.resolve_deep(&Type::Var(field_var)))
+            }
         }
     }
 }
 
-impl Type {
-    fn unify(&self, other: &Self, unifier: &mut Unifier) -> Result<(), Error> {
-        match (self, other) {
-            (Self::Var(v1), other) => {
-                if let Self::Var(v2) = other {
-                    if v1 == v2 {
-                        return Ok(());
-                    }
+impl<'id> Type<'id> {
+    // Algorithm-W unification against the context's persistent substitution:
+    // both sides are resolved to head-normal form first so a variable bound
+    // earlier (possibly by an unrelated call) is always unified against what
+    // it actually stands for. `span` is blamed on any resulting `Error`, and
+    // by convention `self` is reported as the "expected" side and `other` as
+    // the "found" side.
+    fn unify(
+        &self,
+        other: &Self,
+        context: &mut Context<'id>,
+        span: Span,
+    ) -> Result<(), Error<'id>> {
+        let this = context.resolve(self);
+        let other = context.resolve(other);
+        match (&this, &other) {
+            (Self::Var(v1), Self::Var(v2)) if v1 == v2 => Ok(()),
+            (Self::Var(v), ty) | (ty, Self::Var(v)) => {
+                if context.occurs(*v, ty) {
+                    return Err(Error::new(span, ErrorKind::InfiniteType));
                 }
-                unifier.insert(*v1, other.clone());
+                context.subst.insert(*v, ty.clone());
                 Ok(())
             }
             (Self::Fn(k1, v1), Self::Fn(k2, v2)) => {
-                k1.unify(k2, unifier)?;
-                v1.unify(v2, unifier)?;
+                k1.unify(k2, context, span)?;
+                v1.unify(v2, context, span)?;
                 Ok(())
             }
-            _ => Err(Error::Unification),
+            (Self::Con(a), Self::Con(b)) if a == b => Ok(()),
+            (
+                Self::Record {
+                    fields: f1,
+                    rest: r1,
+                },
+                Self::Record {
+                    fields: f2,
+                    rest: r2,
+                },
+            ) => {
+                let mut only1 = BTreeMap::new();
+                for (label, ty1) in f1 {
+                    match f2.get(label) {
+                        Some(ty2) => ty1.unify(ty2, context, span)?,
+                        None => {
+                            only1.insert(*label, ty1.clone());
+                        }
+                    }
+                }
+                let only2: BTreeMap<_, _> = f2
+                    .iter()
+                    .filter(|(label, _)| !f1.contains_key(*label))
+                    .map(|(label, ty)| (*label, ty.clone()))
+                    .collect();
+                match (r1, r2) {
+                    (None, None) if only1.is_empty() && only2.is_empty() => Ok(()),
+                    (None, None) => Err(Error::new(
+                        span,
+                        ErrorKind::Unification {
+                            expected: this.clone(),
+                            found: other.clone(),
+                        },
+                    )),
+                    (Some(v1), None) if only1.is_empty() => Type::Var(*v1).unify(
+                        &Type::Record {
+                            fields: only2,
+                            rest: None,
+                        },
+                        context,
+                        span,
+                    ),
+                    (Some(_), None) => Err(Error::new(
+                        span,
+                        ErrorKind::Unification {
+                            expected: this.clone(),
+                            found: other.clone(),
+                        },
+                    )),
+                    (None, Some(v2)) if only2.is_empty() => Type::Var(*v2).unify(
+                        &Type::Record {
+                            fields: only1,
+                            rest: None,
+                        },
+                        context,
+                        span,
+                    ),
+                    (None, Some(_)) => Err(Error::new(
+                        span,
+                        ErrorKind::Unification {
+                            expected: this.clone(),
+                            found: other.clone(),
+                        },
+                    )),
+                    (Some(v1), Some(v2)) => {
+                        let tail = context.fresh();
+                        Type::Var(*v1).unify(
+                            &Type::Record {
+                                fields: only2,
+                                rest: Some(tail),
+                            },
+                            context,
+                            span,
+                        )?;
+                        Type::Var(*v2).unify(
+                            &Type::Record {
+                                fields: only1,
+                                rest: Some(tail),
+                            },
+                            context,
+                            span,
+                        )
+                    }
+                }
+            }
+            _ => Err(Error::new(
+                span,
+                ErrorKind::Unification {
+                    expected: this.clone(),
+                    found: other.clone(),
+                },
+            )),
         }
     }
 
-    fn subst(&mut self, unifier: &Unifier) {
+    fn subst(&mut self, mapping: &Subst<'id>) {
         match self {
             Type::Var(var) => {
-                if let Some(ty) = unifier.get(var) {
+                if let Some(ty) = mapping.get(var) {
                     *self = ty.clone();
                 }
             }
             Type::Fn(k, v) => {
-                k.subst(unifier);
-                v.subst(unifier);
+                k.subst(mapping);
+                v.subst(mapping);
+            }
+            Type::Con(_) => {}
+            Type::Record { fields, rest } => {
+                for ty in fields.values_mut() {
+                    ty.subst(mapping);
+                }
+                if let Some(r) = rest {
+                    if let Some(Type::Var(new_r)) = mapping.get(r) {
+                        *rest = Some(*new_r);
+                    }
+                }
             }
         }
     }
 }
 
-impl Scheme {
-    fn from(ty: Type) -> Self {
+impl<'id> Scheme<'id> {
+    fn from(ty: Type<'id>) -> Self {
         Self {
             bounds: Vec::new(),
+            constraints: Vec::new(),
             ty,
         }
     }
@@ -160,14 +511,29 @@ impl<'id> Context<'id> {
         Self {
             vars: 0,
             scopes: Vec::new(),
+            subst: HashMap::new(),
+            resolver: None,
+            instances: BTreeSet::new(),
+            wanted: Vec::new(),
+        }
+    }
+
+    fn with_resolver(resolver: impl SymbolResolver<'id> + 'id) -> Self {
+        Self {
+            resolver: Some(Box::new(resolver)),
+            ..Self::new()
         }
     }
 
-    fn last(&self) -> &HashMap<Id<'id>, Scheme> {
+    fn add_instance(&mut self, class: Id<'id>, con: Id<'id>) {
+        self.instances.insert((class, con));
+    }
+
+    fn last(&self) -> &HashMap<Id<'id>, Scheme<'id>> {
         self.scopes.last().expect("never entered a scope")
     }
 
-    fn last_mut(&mut self) -> &mut HashMap<Id<'id>, Scheme> {
+    fn last_mut(&mut self) -> &mut HashMap<Id<'id>, Scheme<'id>> {
         self.scopes.last_mut().expect("never entered a scope")
     }
 
@@ -185,7 +551,7 @@ impl<'id> Context<'id> {
         out
     }
 
-    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+    fn instantiate(&mut self, scheme: &Scheme<'id>) -> Type<'id> {
         let mut unifier = HashMap::new();
         for b in &scheme.bounds {
             let f = self.fresh();
@@ -193,28 +559,660 @@ impl<'id> Context<'id> {
         }
         let mut ty = scheme.ty.clone();
         ty.subst(&unifier);
+        for constraint in &scheme.constraints {
+            let mut ty = constraint.ty.clone();
+            ty.subst(&unifier);
+            self.wanted.push(Constraint {
+                class: constraint.class,
+                ty,
+            });
+        }
         ty
     }
 
-    fn get(&mut self, id: Id<'id>) -> Result<Type, Error> {
-        let mut result: Option<Scheme> = None;
+    fn get(&mut self, id: Id<'id>, span: Span) -> Result<Type<'id>, Error<'id>> {
+        let mut result: Option<Scheme<'id>> = None;
         for scope in self.scopes.iter().rev() {
             if let Some(scheme) = scope.get(id) {
                 result = Some(scheme.clone());
                 break;
             }
         }
-        if let Some(scheme) = result {
-            let ty = self.instantiate(&scheme);
-            return Ok(ty);
+        if result.is_none() {
+            result = self.resolver.as_ref().and_then(|r| r.resolve(id));
+        }
+        match result {
+            Some(scheme) => Ok(self.instantiate(&scheme)),
+            None => Err(Error::new(span, ErrorKind::Undefined)),
         }
-
-        Err(Error::Undefined)
     }
 
-    fn insert(&mut self, id: Id<'id>, scheme: Scheme) {
+    fn insert(&mut self, id: Id<'id>, scheme: Scheme<'id>) {
         self.last_mut().insert(id, scheme);
     }
+
+    // Chases `Var(v)` through the substitution until it lands on a
+    // non-variable or an unbound variable. Does not recurse into `Fn`, so
+    // callers that need a fully-resolved tree should go through
+    // `resolve_deep` instead.
+    fn resolve(&self, ty: &Type<'id>) -> Type<'id> {
+        match ty {
+            Type::Var(v) => match self.subst.get(v) {
+                Some(bound) => self.resolve(bound),
+                None => Type::Var(*v),
+            },
+            other => other.clone(),
+        }
+    }
+
+    fn resolve_deep(&self, ty: &Type<'id>) -> Type<'id> {
+        match self.resolve(ty) {
+            Type::Var(v) => Type::Var(v),
+            Type::Fn(k, v) => Type::Fn(
+                Box::new(self.resolve_deep(&k)),
+                Box::new(self.resolve_deep(&v)),
+            ),
+            Type::Con(name) => Type::Con(name),
+            Type::Record { fields, rest } => {
+                let (fields, rest) = self.resolve_row(&fields, rest);
+                Type::Record { fields, rest }
+            }
+        }
+    }
+
+    // Resolves a record's fields and, if its row variable has since been
+    // bound to a further record (by unification), flattens that record's
+    // fields in too, following the row chain until it ends in either a
+    // closed record or an unbound row variable.
+    fn resolve_row(
+        &self,
+        fields: &BTreeMap<Id<'id>, Type<'id>>,
+        rest: Option<Var>,
+    ) -> (BTreeMap<Id<'id>, Type<'id>>, Option<Var>) {
+        let mut merged: BTreeMap<Id<'id>, Type<'id>> = fields
+            .iter()
+            .map(|(label, ty)| (*label, self.resolve_deep(ty)))
+            .collect();
+        let rest = match rest {
+            None => None,
+            Some(v) => match self.resolve(&Type::Var(v)) {
+                Type::Var(v) => Some(v),
+                Type::Record {
+                    fields: more,
+                    rest: deeper,
+                } => {
+                    let (more, rest) = self.resolve_row(&more, deeper);
+                    for (label, ty) in more {
+                        merged.entry(label).or_insert(ty);
+                    }
+                    rest
+                }
+                _ => unreachable!("row variable bound to a non-record type"),
+            },
+        };
+        (merged, rest)
+    }
+
+    // True when `v` appears in the resolved form of `ty`; binding `v` to a
+    // type that still mentions `v` would build an infinite type.
+    fn occurs(&self, v: Var, ty: &Type<'id>) -> bool {
+        match self.resolve(ty) {
+            Type::Var(other) => v == other,
+            Type::Fn(k, b) => self.occurs(v, &k) || self.occurs(v, &b),
+            Type::Con(_) => false,
+            Type::Record { fields, rest } => {
+                fields.values().any(|ty| self.occurs(v, ty))
+                    || rest.is_some_and(|r| self.occurs(v, &Type::Var(r)))
+            }
+        }
+    }
+
+    fn free_vars(&self, ty: &Type<'id>) -> BTreeSet<Var> {
+        let mut out = BTreeSet::new();
+        self.free_vars_into(ty, &mut out);
+        out
+    }
+
+    fn free_vars_into(&self, ty: &Type<'id>, out: &mut BTreeSet<Var>) {
+        match self.resolve(ty) {
+            Type::Var(v) => {
+                out.insert(v);
+            }
+            Type::Fn(k, v) => {
+                self.free_vars_into(&k, out);
+                self.free_vars_into(&v, out);
+            }
+            Type::Con(_) => {}
+            Type::Record { fields, rest } => {
+                for ty in fields.values() {
+                    self.free_vars_into(ty, out);
+                }
+                if let Some(r) = rest {
+                    self.free_vars_into(&Type::Var(r), out);
+                }
+            }
+        }
+    }
+
+    // Free variables of every binding still in scope, i.e. the variables
+    // that must stay monomorphic because they're shared with an enclosing
+    // lambda rather than owned by the `let` being generalized.
+    fn env_free_vars(&self) -> BTreeSet<Var> {
+        let mut out = BTreeSet::new();
+        for scope in &self.scopes {
+            for scheme in scope.values() {
+                let mut ftv = self.free_vars(&scheme.ty);
+                for bound in &scheme.bounds {
+                    ftv.remove(bound);
+                }
+                out.extend(ftv);
+            }
+        }
+        out
+    }
+
+    // Generalizes `ty` into a `Scheme` quantified over every free variable
+    // that isn't also free in the surrounding context, i.e. standard
+    // let-polymorphism. `constraints` are the obligations incurred while
+    // inferring `ty` (e.g. `Num a` from calling a constrained builtin);
+    // each is solved against `bounds` and the current substitution, kept on
+    // the resulting `Scheme` only if it still mentions a variable this
+    // binding itself generalizes.
+    fn generalize(
+        &mut self,
+        ty: Type<'id>,
+        constraints: Vec<Constraint<'id>>,
+        span: Span,
+    ) -> Result<Scheme<'id>, Error<'id>> {
+        let ty = self.resolve_deep(&ty);
+        let free = self.free_vars(&ty);
+        let env = self.env_free_vars();
+        let bounds: Vec<Var> = free.difference(&env).copied().collect();
+        let constraints = self.solve_constraints(constraints, &bounds, span)?;
+        Ok(Scheme {
+            bounds,
+            constraints,
+            ty,
+        })
+    }
+
+    // Resolves each constraint through the current substitution: a
+    // concrete `Con` must have a matching instance or inference fails with
+    // `Error::NoInstance`; a variable still owned by the binding being
+    // generalized (i.e. in `bounds`) is retained on its `Scheme`; anything
+    // else is left pending for an outer scope to solve.
+    fn solve_constraints(
+        &mut self,
+        constraints: Vec<Constraint<'id>>,
+        bounds: &[Var],
+        span: Span,
+    ) -> Result<Vec<Constraint<'id>>, Error<'id>> {
+        let mut retained = Vec::new();
+        for constraint in constraints {
+            match self.resolve_deep(&constraint.ty) {
+                Type::Con(name) if self.instances.contains(&(constraint.class, name)) => {}
+                Type::Con(name) => {
+                    return Err(Error::new(
+                        span,
+                        ErrorKind::NoInstance {
+                            class: constraint.class,
+                            ty: Type::Con(name),
+                        },
+                    ));
+                }
+                Type::Var(v) if bounds.contains(&v) => retained.push(Constraint {
+                    class: constraint.class,
+                    ty: Type::Var(v),
+                }),
+                other => self.wanted.push(Constraint {
+                    class: constraint.class,
+                    ty: other,
+                }),
+            }
+        }
+        Ok(retained)
+    }
+
+    // Solves every constraint still pending once nothing remains to defer
+    // to: run once, after the outermost expression of a program has been
+    // inferred, to catch obligations (like the `Num a` from `double` being
+    // applied to a `Bool`) that no enclosing `let` was left to absorb.
+    fn solve_wanted(&mut self, span: Span) -> Result<(), Error<'id>> {
+        let wanted = std::mem::take(&mut self.wanted);
+        self.solve_constraints(wanted, &[], span)?;
+        Ok(())
+    }
+}
+
+// syntax
+//
+// A small recursive-descent parser for the concrete syntax documented on
+// `ExprKind`'s variants: identifiers, `x -> x`, `let f x y = v; b`,
+// application by juxtaposition (`f x`), parens, `when`/`is`, records, and
+// field access. Turns source text into the same spanned `Expr` trees the
+// rest of this file builds by hand.
+
+const UNEXPECTED_EOF: &str = "unexpected end of input";
+
+// A lex or parse failure, rendered the same way as a type `Error` so the
+// REPL can show both kinds of mistake uniformly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ParseError {
+    message: String,
+    span: Span,
+}
+
+impl ParseError {
+    fn render(&self, source: &str) -> String {
+        render_span(self.span, &self.message, source)
+    }
+
+    // True when the failure was simply running out of input, e.g. an
+    // unclosed paren or a `let` missing its body — the REPL treats this as
+    // "keep reading" rather than a real error.
+    fn is_eof(&self) -> bool {
+        self.message == UNEXPECTED_EOF
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token<'id> {
+    Id(Id<'id>),
+    Int(i64),
+    True,
+    False,
+    Let,
+    When,
+    Is,
+    Underscore,
+    Arrow,
+    Equals,
+    Semicolon,
+    Comma,
+    Colon,
+    Dot,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Eof,
+}
+
+struct Lexer<'id> {
+    source: &'id str,
+    bytes: &'id [u8],
+    pos: usize,
+}
+
+impl<'id> Lexer<'id> {
+    fn new(source: &'id str) -> Self {
+        Self {
+            source,
+            bytes: source.as_bytes(),
+            pos: 0,
+        }
+    }
+
+    fn tokenize(mut self) -> Result<Vec<(Token<'id>, Span)>, ParseError> {
+        let mut tokens = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let start = self.pos;
+            if self.pos >= self.bytes.len() {
+                tokens.push((Token::Eof, Span { start, end: start }));
+                return Ok(tokens);
+            }
+            let c = self.bytes[self.pos] as char;
+            let token = if c.is_ascii_digit() {
+                self.lex_int()?
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                self.lex_ident()
+            } else {
+                self.lex_symbol()?
+            };
+            tokens.push((
+                token,
+                Span {
+                    start,
+                    end: self.pos,
+                },
+            ));
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.bytes.len() && (self.bytes[self.pos] as char).is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn lex_int(&mut self) -> Result<Token<'id>, ParseError> {
+        let start = self.pos;
+        while self.pos < self.bytes.len() && (self.bytes[self.pos] as char).is_ascii_digit() {
+            self.pos += 1;
+        }
+        match self.source[start..self.pos].parse() {
+            Ok(n) => Ok(Token::Int(n)),
+            Err(_) => Err(ParseError {
+                span: Span {
+                    start,
+                    end: self.pos,
+                },
+                message: "integer literal out of range".to_string(),
+            }),
+        }
+    }
+
+    fn lex_ident(&mut self) -> Token<'id> {
+        let start = self.pos;
+        while self.pos < self.bytes.len()
+            && ((self.bytes[self.pos] as char).is_ascii_alphanumeric()
+                || self.bytes[self.pos] == b'_')
+        {
+            self.pos += 1;
+        }
+        match &self.source[start..self.pos] {
+            "let" => Token::Let,
+            "when" => Token::When,
+            "is" => Token::Is,
+            "true" => Token::True,
+            "false" => Token::False,
+            "_" => Token::Underscore,
+            name => Token::Id(name),
+        }
+    }
+
+    fn lex_symbol(&mut self) -> Result<Token<'id>, ParseError> {
+        let start = self.pos;
+        if self.source[start..].starts_with("->") {
+            self.pos += 2;
+            return Ok(Token::Arrow);
+        }
+        let token = match self.bytes[self.pos] as char {
+            '=' => Token::Equals,
+            ';' => Token::Semicolon,
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            '.' => Token::Dot,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '{' => Token::LBrace,
+            '}' => Token::RBrace,
+            other => {
+                self.pos += 1;
+                return Err(ParseError {
+                    span: Span {
+                        start,
+                        end: self.pos,
+                    },
+                    message: format!("unexpected character {other:?}"),
+                });
+            }
+        };
+        self.pos += 1;
+        Ok(token)
+    }
+}
+
+struct Parser<'id> {
+    tokens: Vec<(Token<'id>, Span)>,
+    pos: usize,
+}
+
+impl<'id> Parser<'id> {
+    fn new(tokens: Vec<(Token<'id>, Span)>) -> Self {
+        Self { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> &Token<'id> {
+        &self.tokens[self.pos].0
+    }
+
+    fn peek_at(&self, offset: usize) -> &Token<'id> {
+        let i = (self.pos + offset).min(self.tokens.len() - 1);
+        &self.tokens[i].0
+    }
+
+    fn peek_span(&self) -> Span {
+        self.tokens[self.pos].1
+    }
+
+    fn advance(&mut self) -> (Token<'id>, Span) {
+        let entry = self.tokens[self.pos].clone();
+        if self.pos + 1 < self.tokens.len() {
+            self.pos += 1;
+        }
+        entry
+    }
+
+    fn unexpected(&self) -> ParseError {
+        if matches!(self.peek(), Token::Eof) {
+            ParseError {
+                span: self.peek_span(),
+                message: UNEXPECTED_EOF.to_string(),
+            }
+        } else {
+            ParseError {
+                span: self.peek_span(),
+                message: format!("unexpected {:?}", self.peek()),
+            }
+        }
+    }
+
+    fn expect_token(&mut self, expected: Token<'id>) -> Result<Span, ParseError> {
+        if *self.peek() == expected {
+            Ok(self.advance().1)
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn expect_id(&mut self) -> Result<Id<'id>, ParseError> {
+        match *self.peek() {
+            Token::Id(name) => {
+                self.advance();
+                Ok(name)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn starts_atom(&self) -> bool {
+        matches!(
+            self.peek(),
+            Token::Id(_)
+                | Token::Int(_)
+                | Token::True
+                | Token::False
+                | Token::LParen
+                | Token::LBrace
+        )
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr<'id>, ParseError> {
+        match self.peek() {
+            Token::Let => self.parse_let(),
+            Token::When => self.parse_when(),
+            Token::Id(_) if *self.peek_at(1) == Token::Arrow => self.parse_fn(),
+            _ => self.parse_app(),
+        }
+    }
+
+    fn parse_let(&mut self) -> Result<Expr<'id>, ParseError> {
+        let start = self.peek_span().start;
+        self.advance(); // `let`
+        let name = self.expect_id()?;
+        let mut params = Vec::new();
+        while let Token::Id(_) = self.peek() {
+            let (token, span) = self.advance();
+            if let Token::Id(p) = token {
+                params.push((p, span));
+            }
+        }
+        self.expect_token(Token::Equals)?;
+        let mut value = self.parse_expr()?;
+        // `let f x y = v` desugars to `let f = x -> y -> v`: the rest of this
+        // file always represents a multi-argument binding as a chain of
+        // `Fn`s rather than populating `Let`'s (otherwise-unused) params.
+        for (param, param_span) in params.into_iter().rev() {
+            let span = Span {
+                start: param_span.start,
+                end: value.span.end,
+            };
+            value = Expr::new(span, ExprKind::Fn(param, Box::new(value)));
+        }
+        self.expect_token(Token::Semicolon)?;
+        let body = self.parse_expr()?;
+        let end = body.span.end;
+        Ok(Expr::new(
+            Span { start, end },
+            ExprKind::Let(name, Vec::new(), Box::new(value), Box::new(body)),
+        ))
+    }
+
+    fn parse_fn(&mut self) -> Result<Expr<'id>, ParseError> {
+        let start = self.peek_span().start;
+        let name = self.expect_id()?;
+        self.expect_token(Token::Arrow)?;
+        let body = self.parse_expr()?;
+        let end = body.span.end;
+        Ok(Expr::new(
+            Span { start, end },
+            ExprKind::Fn(name, Box::new(body)),
+        ))
+    }
+
+    fn parse_when(&mut self) -> Result<Expr<'id>, ParseError> {
+        let start = self.peek_span().start;
+        self.advance(); // `when`
+        let scrutinee = self.parse_app()?;
+        self.expect_token(Token::Is)?;
+        let mut arms = Vec::new();
+        loop {
+            let pattern = self.parse_pattern()?;
+            self.expect_token(Token::Arrow)?;
+            let body = self.parse_expr()?;
+            arms.push((pattern, body));
+            if matches!(self.peek(), Token::Comma) {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        let end = arms.last().expect("loop runs at least once").1.span.end;
+        Ok(Expr::new(
+            Span { start, end },
+            ExprKind::When(Box::new(scrutinee), arms),
+        ))
+    }
+
+    fn parse_pattern(&mut self) -> Result<Pattern<'id>, ParseError> {
+        let pattern = match *self.peek() {
+            Token::Int(n) => Pattern::Int(n),
+            Token::Underscore => Pattern::Wildcard,
+            Token::Id(name) => Pattern::Var(name),
+            _ => return Err(self.unexpected()),
+        };
+        self.advance();
+        Ok(pattern)
+    }
+
+    // Application by juxtaposition: a run of postfix expressions, left
+    // associative, e.g. `f x y` parses as `(f x) y`.
+    fn parse_app(&mut self) -> Result<Expr<'id>, ParseError> {
+        let mut expr = self.parse_postfix()?;
+        while self.starts_atom() {
+            let arg = self.parse_postfix()?;
+            let span = Span {
+                start: expr.span.start,
+                end: arg.span.end,
+            };
+            expr = Expr::new(span, ExprKind::Call(Box::new(expr), Box::new(arg)));
+        }
+        Ok(expr)
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr<'id>, ParseError> {
+        let mut expr = self.parse_atom()?;
+        while matches!(self.peek(), Token::Dot) {
+            self.advance();
+            let (label, label_span) = match *self.peek() {
+                Token::Id(name) => (name, self.advance().1),
+                _ => return Err(self.unexpected()),
+            };
+            let span = Span {
+                start: expr.span.start,
+                end: label_span.end,
+            };
+            expr = Expr::new(span, ExprKind::Field(Box::new(expr), label));
+        }
+        Ok(expr)
+    }
+
+    fn parse_atom(&mut self) -> Result<Expr<'id>, ParseError> {
+        let (token, span) = self.advance();
+        match token {
+            Token::Id(name) => Ok(Expr::new(span, ExprKind::Id(name))),
+            Token::Int(n) => Ok(Expr::new(span, ExprKind::Int(n))),
+            Token::True => Ok(Expr::new(span, ExprKind::Bool(true))),
+            Token::False => Ok(Expr::new(span, ExprKind::Bool(false))),
+            Token::LParen => {
+                let inner = self.parse_expr()?;
+                let close = self.expect_token(Token::RParen)?;
+                Ok(Expr::new(
+                    Span {
+                        start: span.start,
+                        end: close.end,
+                    },
+                    inner.kind,
+                ))
+            }
+            Token::LBrace => self.parse_record(span),
+            Token::Eof => Err(ParseError {
+                span,
+                message: UNEXPECTED_EOF.to_string(),
+            }),
+            _ => Err(ParseError {
+                span,
+                message: format!("expected an expression, found {token:?}"),
+            }),
+        }
+    }
+
+    fn parse_record(&mut self, start_span: Span) -> Result<Expr<'id>, ParseError> {
+        let mut fields = Vec::new();
+        if !matches!(self.peek(), Token::RBrace) {
+            loop {
+                let label = self.expect_id()?;
+                self.expect_token(Token::Colon)?;
+                let value = self.parse_expr()?;
+                fields.push((label, value));
+                if matches!(self.peek(), Token::Comma) {
+                    self.advance();
+                } else {
+                    break;
+                }
+            }
+        }
+        let close = self.expect_token(Token::RBrace)?;
+        Ok(Expr::new(
+            Span {
+                start: start_span.start,
+                end: close.end,
+            },
+            ExprKind::Record(fields),
+        ))
+    }
+}
+
+// Parses a complete expression from `source`, requiring every token to be
+// consumed.
+fn parse(source: &str) -> Result<Expr<'_>, ParseError> {
+    let tokens = Lexer::new(source).tokenize()?;
+    let mut parser = Parser::new(tokens);
+    let expr = parser.parse_expr()?;
+    parser.expect_token(Token::Eof)?;
+    Ok(expr)
 }
 
 #[cfg(test)]
@@ -224,12 +1222,12 @@ mod test {
     #[test]
     fn test_undefined1() {
         let mut context = Context::new();
-        let e = Expr::Id("xyz");
+        let e = Expr::new(Span::DUMMY, ExprKind::Id("xyz"));
         let flags = Flags::all();
         let result = e.infer(&mut context, &flags);
         assert_eq!(
             result,
-            Err(Error::Undefined),
+            Err(Error::new(Span::DUMMY, ErrorKind::Undefined)),
             "use of undefined variable results in type error"
         );
     }
@@ -238,23 +1236,35 @@ mod test {
     fn test_undefined2() {
         // y -> let id = x -> x; id x
         let mut context = Context::new();
-        let e = Expr::Fn(
-            "y",
-            Box::new(Expr::Let(
-                "id",
-                Vec::new(),
-                Box::new(Expr::Fn("x", Box::new(Expr::Id("x")))),
-                Box::new(Expr::Call(
-                    Box::new(Expr::Id("id")),
-                    Box::new(Expr::Id("x")),
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::Fn(
+                "y",
+                Box::new(Expr::new(
+                    Span::DUMMY,
+                    ExprKind::Let(
+                        "id",
+                        Vec::new(),
+                        Box::new(Expr::new(
+                            Span::DUMMY,
+                            ExprKind::Fn("x", Box::new(Expr::new(Span::DUMMY, ExprKind::Id("x")))),
+                        )),
+                        Box::new(Expr::new(
+                            Span::DUMMY,
+                            ExprKind::Call(
+                                Box::new(Expr::new(Span::DUMMY, ExprKind::Id("id"))),
+                                Box::new(Expr::new(Span::DUMMY, ExprKind::Id("x"))),
+                            ),
+                        )),
+                    ),
                 )),
-            )),
+            ),
         );
         let flags = Flags::all();
         let result = e.infer(&mut context, &flags);
         assert_eq!(
             result,
-            Err(Error::Undefined),
+            Err(Error::new(Span::DUMMY, ErrorKind::Undefined)),
             "use of variable defined in inner scope"
         );
     }
@@ -262,7 +1272,10 @@ mod test {
     #[test]
     fn infer_ident_fn() {
         let mut context = Context::new();
-        let e = Expr::Fn("x", Box::new(Expr::Id("x")));
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::Fn("x", Box::new(Expr::new(Span::DUMMY, ExprKind::Id("x")))),
+        );
         let flags = Flags::all();
         let result = e.infer(&mut context, &flags);
         assert_eq!(
@@ -275,13 +1288,24 @@ mod test {
     #[test]
     fn test_let() {
         let mut context = Context::new();
-        let id = Expr::Fn("x", Box::new(Expr::Id("x")));
-        let e = Expr::Let("id", Vec::new(), Box::new(id), Box::new(Expr::Id("id")));
+        let id = Expr::new(
+            Span::DUMMY,
+            ExprKind::Fn("x", Box::new(Expr::new(Span::DUMMY, ExprKind::Id("x")))),
+        );
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::Let(
+                "id",
+                Vec::new(),
+                Box::new(id),
+                Box::new(Expr::new(Span::DUMMY, ExprKind::Id("id"))),
+            ),
+        );
         let flags = Flags::all();
         let result = e.infer(&mut context, &flags);
         assert_eq!(
             result,
-            Ok(Type::Fn(Box::new(Type::Var(0)), Box::new(Type::Var(0)))),
+            Ok(Type::Fn(Box::new(Type::Var(1)), Box::new(Type::Var(1)))),
             "use of let var results in type substitution"
         );
     }
@@ -290,17 +1314,29 @@ mod test {
     fn test_identity_identity() {
         // y -> let id = x -> x; id id
         let mut context = Context::new();
-        let e = Expr::Fn(
-            "y",
-            Box::new(Expr::Let(
-                "id",
-                Vec::new(),
-                Box::new(Expr::Fn("x", Box::new(Expr::Id("x")))),
-                Box::new(Expr::Call(
-                    Box::new(Expr::Id("id")),
-                    Box::new(Expr::Id("id")),
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::Fn(
+                "y",
+                Box::new(Expr::new(
+                    Span::DUMMY,
+                    ExprKind::Let(
+                        "id",
+                        Vec::new(),
+                        Box::new(Expr::new(
+                            Span::DUMMY,
+                            ExprKind::Fn("x", Box::new(Expr::new(Span::DUMMY, ExprKind::Id("x")))),
+                        )),
+                        Box::new(Expr::new(
+                            Span::DUMMY,
+                            ExprKind::Call(
+                                Box::new(Expr::new(Span::DUMMY, ExprKind::Id("id"))),
+                                Box::new(Expr::new(Span::DUMMY, ExprKind::Id("id"))),
+                            ),
+                        )),
+                    ),
                 )),
-            )),
+            ),
         );
         let flags = Flags::all();
         let result = e.infer(&mut context, &flags);
@@ -308,7 +1344,7 @@ mod test {
             result,
             Ok(Type::Fn(
                 Box::new(Type::Var(0)),
-                Box::new(Type::Fn(Box::new(Type::Var(1)), Box::new(Type::Var(1))))
+                Box::new(Type::Fn(Box::new(Type::Var(3)), Box::new(Type::Var(3))))
             )),
             "ident of ident has ident type"
         )
@@ -318,23 +1354,37 @@ mod test {
     fn test_identity_application() {
         // y -> let id = x -> x; id y
         let mut context = Context::new();
-        let e = Expr::Fn(
-            "y",
-            Box::new(Expr::Let(
-                "id",
-                Vec::new(),
-                Box::new(Expr::Fn("x", Box::new(Expr::Id("x")))),
-                Box::new(Expr::Call(
-                    Box::new(Expr::Id("id")),
-                    Box::new(Expr::Id("y")),
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::Fn(
+                "y",
+                Box::new(Expr::new(
+                    Span::DUMMY,
+                    ExprKind::Let(
+                        "id",
+                        Vec::new(),
+                        Box::new(Expr::new(
+                            Span::DUMMY,
+                            ExprKind::Fn("x", Box::new(Expr::new(Span::DUMMY, ExprKind::Id("x")))),
+                        )),
+                        Box::new(Expr::new(
+                            Span::DUMMY,
+                            ExprKind::Call(
+                                Box::new(Expr::new(Span::DUMMY, ExprKind::Id("id"))),
+                                Box::new(Expr::new(Span::DUMMY, ExprKind::Id("y"))),
+                            ),
+                        )),
+                    ),
                 )),
-            )),
+            ),
         );
         let flags = Flags::all();
-        let result = e.infer(&mut context, &flags);
+        let result = e
+            .infer(&mut context, &flags)
+            .map(|ty| context.resolve_deep(&ty));
         assert_eq!(
             result,
-            Ok(Type::Fn(Box::new(Type::Var(0)), Box::new(Type::Var(0)))),
+            Ok(Type::Fn(Box::new(Type::Var(3)), Box::new(Type::Var(3)))),
             "ident of var has var type"
         )
     }
@@ -346,58 +1396,694 @@ mod test {
         // first (id id) (id first)
         //     => a -> a
         let mut context = Context::new();
-        let expr = Expr::Let(
-            "id",
-            vec![],
-            Box::new(Expr::Fn("x", Box::new(Expr::Id("x")))),
-            Box::new(Expr::Let(
-                "first",
+        let expr = Expr::new(
+            Span::DUMMY,
+            ExprKind::Let(
+                "id",
                 vec![],
-                Box::new(Expr::Fn(
-                    "x",
-                    Box::new(Expr::Fn("y", Box::new(Expr::Id("x")))),
+                Box::new(Expr::new(
+                    Span::DUMMY,
+                    ExprKind::Fn("x", Box::new(Expr::new(Span::DUMMY, ExprKind::Id("x")))),
                 )),
-                Box::new(Expr::Call(
-                    Box::new(Expr::Call(
-                        Box::new(Expr::Id("first")),
-                        Box::new(Expr::Call(
-                            Box::new(Expr::Id("id")),
-                            Box::new(Expr::Id("id")),
+                Box::new(Expr::new(
+                    Span::DUMMY,
+                    ExprKind::Let(
+                        "first",
+                        vec![],
+                        Box::new(Expr::new(
+                            Span::DUMMY,
+                            ExprKind::Fn(
+                                "x",
+                                Box::new(Expr::new(
+                                    Span::DUMMY,
+                                    ExprKind::Fn(
+                                        "y",
+                                        Box::new(Expr::new(Span::DUMMY, ExprKind::Id("x"))),
+                                    ),
+                                )),
+                            ),
                         )),
-                    )),
-                    Box::new(Expr::Call(
-                        Box::new(Expr::Id("id")),
-                        Box::new(Expr::Id("first")),
-                    )),
+                        Box::new(Expr::new(
+                            Span::DUMMY,
+                            ExprKind::Call(
+                                Box::new(Expr::new(
+                                    Span::DUMMY,
+                                    ExprKind::Call(
+                                        Box::new(Expr::new(Span::DUMMY, ExprKind::Id("first"))),
+                                        Box::new(Expr::new(
+                                            Span::DUMMY,
+                                            ExprKind::Call(
+                                                Box::new(Expr::new(
+                                                    Span::DUMMY,
+                                                    ExprKind::Id("id"),
+                                                )),
+                                                Box::new(Expr::new(
+                                                    Span::DUMMY,
+                                                    ExprKind::Id("id"),
+                                                )),
+                                            ),
+                                        )),
+                                    ),
+                                )),
+                                Box::new(Expr::new(
+                                    Span::DUMMY,
+                                    ExprKind::Call(
+                                        Box::new(Expr::new(Span::DUMMY, ExprKind::Id("id"))),
+                                        Box::new(Expr::new(Span::DUMMY, ExprKind::Id("first"))),
+                                    ),
+                                )),
+                            ),
+                        )),
+                    ),
                 )),
-            )),
+            ),
         );
         let flags = Flags::all();
         let result = expr.infer(&mut context, &flags);
         assert_eq!(
             result,
-            Ok(Type::Fn(Box::new(Type::Var(0)), Box::new(Type::Var(0)))),
+            Ok(Type::Fn(Box::new(Type::Var(6)), Box::new(Type::Var(6)))),
             "type checker supports polymorphic identifier function"
         )
     }
-}
 
-fn main() {
-    // y -> let id = x -> x; id x
-    let mut context = Context::new();
-    let expr = Expr::Fn(
-        "y",
-        Box::new(Expr::Let(
-            "id",
-            Vec::new(),
-            Box::new(Expr::Fn("x", Box::new(Expr::Id("x")))),
-            Box::new(Expr::Call(
-                Box::new(Expr::Id("id")),
-                Box::new(Expr::Id("x")),
+    #[test]
+    fn test_occurs_check() {
+        // a = a -> a
+        let mut context = Context::new();
+        let a = context.fresh();
+        let cyclic = Type::Fn(Box::new(Type::Var(a)), Box::new(Type::Var(a)));
+        let result = Type::Var(a).unify(&cyclic, &mut context, Span::DUMMY);
+        assert_eq!(
+            result,
+            Err(Error::new(Span::DUMMY, ErrorKind::InfiniteType)),
+            "binding a variable to a type that contains it would build an infinite type"
+        );
+    }
+
+    #[test]
+    fn test_chained_application_shares_substitution() {
+        // let first = x -> y -> x; (first a) b => type of a
+        let mut context = Context::new();
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::Let(
+                "first",
+                Vec::new(),
+                Box::new(Expr::new(
+                    Span::DUMMY,
+                    ExprKind::Fn(
+                        "x",
+                        Box::new(Expr::new(
+                            Span::DUMMY,
+                            ExprKind::Fn("y", Box::new(Expr::new(Span::DUMMY, ExprKind::Id("x")))),
+                        )),
+                    ),
+                )),
+                Box::new(Expr::new(
+                    Span::DUMMY,
+                    ExprKind::Fn(
+                        "a",
+                        Box::new(Expr::new(
+                            Span::DUMMY,
+                            ExprKind::Fn(
+                                "b",
+                                Box::new(Expr::new(
+                                    Span::DUMMY,
+                                    ExprKind::Call(
+                                        Box::new(Expr::new(
+                                            Span::DUMMY,
+                                            ExprKind::Call(
+                                                Box::new(Expr::new(
+                                                    Span::DUMMY,
+                                                    ExprKind::Id("first"),
+                                                )),
+                                                Box::new(Expr::new(Span::DUMMY, ExprKind::Id("a"))),
+                                            ),
+                                        )),
+                                        Box::new(Expr::new(Span::DUMMY, ExprKind::Id("b"))),
+                                    ),
+                                )),
+                            ),
+                        )),
+                    ),
+                )),
+            ),
+        );
+        let flags = Flags::all();
+        let result = e
+            .infer(&mut context, &flags)
+            .map(|ty| context.resolve_deep(&ty));
+        assert_eq!(
+            result,
+            Ok(Type::Fn(
+                Box::new(Type::Var(7)),
+                Box::new(Type::Fn(Box::new(Type::Var(3)), Box::new(Type::Var(7))))
+            )),
+            "binding from the first call is visible when unifying the second"
+        )
+    }
+
+    #[test]
+    fn test_int_literal() {
+        let mut context = Context::new();
+        let e = Expr::new(Span::DUMMY, ExprKind::Int(42));
+        let flags = Flags::all();
+        let result = e.infer(&mut context, &flags);
+        assert_eq!(result, Ok(Type::Con("Int")), "integer literal has type Int");
+    }
+
+    #[test]
+    fn test_bool_literal() {
+        let mut context = Context::new();
+        let e = Expr::new(Span::DUMMY, ExprKind::Bool(true));
+        let flags = Flags::all();
+        let result = e.infer(&mut context, &flags);
+        assert_eq!(
+            result,
+            Ok(Type::Con("Bool")),
+            "boolean literal has type Bool"
+        );
+    }
+
+    #[test]
+    fn test_con_mismatch() {
+        let mut context = Context::new();
+        let result = Type::Con("Int").unify(&Type::Con("Bool"), &mut context, Span::DUMMY);
+        assert_eq!(
+            result,
+            Err(Error::new(
+                Span::DUMMY,
+                ErrorKind::Unification {
+                    expected: Type::Con("Int"),
+                    found: Type::Con("Bool"),
+                }
+            )),
+            "distinct base types do not unify"
+        );
+    }
+
+    #[test]
+    fn test_when_literal_arms() {
+        // when 1 is 0 -> false, _ -> true
+        let mut context = Context::new();
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::When(
+                Box::new(Expr::new(Span::DUMMY, ExprKind::Int(1))),
+                vec![
+                    (
+                        Pattern::Int(0),
+                        Expr::new(Span::DUMMY, ExprKind::Bool(false)),
+                    ),
+                    (
+                        Pattern::Wildcard,
+                        Expr::new(Span::DUMMY, ExprKind::Bool(true)),
+                    ),
+                ],
+            ),
+        );
+        let flags = Flags::all();
+        let result = e.infer(&mut context, &flags);
+        assert_eq!(
+            result,
+            Ok(Type::Con("Bool")),
+            "when expression has the unified type of its arms"
+        );
+    }
+
+    #[test]
+    fn test_when_binder_scopes_to_arm() {
+        // x -> when x is y -> y
+        let mut context = Context::new();
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::Fn(
+                "x",
+                Box::new(Expr::new(
+                    Span::DUMMY,
+                    ExprKind::When(
+                        Box::new(Expr::new(Span::DUMMY, ExprKind::Id("x"))),
+                        vec![(Pattern::Var("y"), Expr::new(Span::DUMMY, ExprKind::Id("y")))],
+                    ),
+                )),
+            ),
+        );
+        let flags = Flags::all();
+        let result = e.infer(&mut context, &flags);
+        assert_eq!(
+            result,
+            Ok(Type::Fn(Box::new(Type::Var(0)), Box::new(Type::Var(0)))),
+            "when binder unifies with the scrutinee and scopes to its arm"
+        );
+    }
+
+    #[test]
+    fn test_when_arm_type_mismatch() {
+        // when true is 0 -> 1 (scrutinee isn't Int)
+        let mut context = Context::new();
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::When(
+                Box::new(Expr::new(Span::DUMMY, ExprKind::Bool(true))),
+                vec![(Pattern::Int(0), Expr::new(Span::DUMMY, ExprKind::Int(1)))],
+            ),
+        );
+        let flags = Flags::all();
+        let result = e.infer(&mut context, &flags);
+        assert_eq!(
+            result,
+            Err(Error::new(
+                Span::DUMMY,
+                ErrorKind::Unification {
+                    expected: Type::Con("Int"),
+                    found: Type::Con("Bool"),
+                }
+            )),
+            "an Int pattern cannot match a Bool scrutinee"
+        );
+    }
+
+    #[test]
+    fn test_record_literal() {
+        // { a: 1, b: true }
+        let mut context = Context::new();
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::Record(vec![
+                ("a", Expr::new(Span::DUMMY, ExprKind::Int(1))),
+                ("b", Expr::new(Span::DUMMY, ExprKind::Bool(true))),
+            ]),
+        );
+        let flags = Flags::all();
+        let result = e.infer(&mut context, &flags);
+        assert_eq!(
+            result,
+            Ok(Type::Record {
+                fields: BTreeMap::from([("a", Type::Con("Int")), ("b", Type::Con("Bool"))]),
+                rest: None,
+            }),
+            "record literal infers a closed record of its field types"
+        );
+    }
+
+    #[test]
+    fn test_field_access() {
+        // { a: 1, b: true }.a
+        let mut context = Context::new();
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::Field(
+                Box::new(Expr::new(
+                    Span::DUMMY,
+                    ExprKind::Record(vec![
+                        ("a", Expr::new(Span::DUMMY, ExprKind::Int(1))),
+                        ("b", Expr::new(Span::DUMMY, ExprKind::Bool(true))),
+                    ]),
+                )),
+                "a",
+            ),
+        );
+        let flags = Flags::all();
+        let result = e.infer(&mut context, &flags);
+        assert_eq!(
+            result,
+            Ok(Type::Con("Int")),
+            "field access yields the field's type"
+        );
+    }
+
+    #[test]
+    fn test_field_access_missing_label_errors() {
+        // { a: 1 }.b
+        let mut context = Context::new();
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::Field(
+                Box::new(Expr::new(
+                    Span::DUMMY,
+                    ExprKind::Record(vec![("a", Expr::new(Span::DUMMY, ExprKind::Int(1)))]),
+                )),
+                "b",
+            ),
+        );
+        let flags = Flags::all();
+        let result = e.infer(&mut context, &flags);
+        match result {
+            Err(Error {
+                kind: ErrorKind::Unification { .. },
+                ..
+            }) => {}
+            other => {
+                panic!("a closed record lacking the accessed label is a type error, got {other:?}")
+            }
+        }
+    }
+
+    #[test]
+    fn test_field_access_is_polymorphic_over_other_fields() {
+        // r -> r.x
+        let mut context = Context::new();
+        let e = Expr::new(
+            Span::DUMMY,
+            ExprKind::Fn(
+                "r",
+                Box::new(Expr::new(
+                    Span::DUMMY,
+                    ExprKind::Field(Box::new(Expr::new(Span::DUMMY, ExprKind::Id("r"))), "x"),
+                )),
+            ),
+        );
+        let flags = Flags::all();
+        let result = e
+            .infer(&mut context, &flags)
+            .map(|ty| context.resolve_deep(&ty));
+        match result {
+            Ok(Type::Fn(param, body)) => match *param {
+                Type::Record { fields, rest } => {
+                    assert!(rest.is_some(), "param record stays open over unused fields");
+                    assert_eq!(
+                        fields.get("x"),
+                        Some(&*body),
+                        "the field's type is threaded through to the result"
+                    );
+                }
+                other => panic!("expected an open record parameter, got {other:?}"),
+            },
+            other => panic!("expected a function type, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_render_points_at_the_offending_span() {
+        let source = "true + 1";
+        let error = Error::new(
+            Span { start: 0, end: 4 },
+            ErrorKind::Unification {
+                expected: Type::Con("Int"),
+                found: Type::Con("Bool"),
+            },
+        );
+        assert_eq!(
+            error.render(source),
+            "true + 1\n^^^^ expected Int, found Bool",
+            "render underlines the primary span and explains the clash"
+        );
+    }
+
+    #[test]
+    fn test_parse_identity() {
+        let mut context = Context::new();
+        let expr = parse("x -> x").expect("valid syntax");
+        let result = expr.infer(&mut context, &Flags::all());
+        assert_eq!(
+            result,
+            Ok(Type::Fn(Box::new(Type::Var(0)), Box::new(Type::Var(0)))),
+            "parsed identity function has the same type as the hand-built tree"
+        );
+    }
+
+    #[test]
+    fn test_parse_let_and_application() {
+        let mut context = Context::new();
+        let expr = parse("let id = x -> x; id 1").expect("valid syntax");
+        let result = expr.infer(&mut context, &Flags::all());
+        assert_eq!(
+            result,
+            Ok(Type::Con("Int")),
+            "let-bound id applies to a literal"
+        );
+    }
+
+    #[test]
+    fn test_parse_multi_param_let_and_juxtaposition() {
+        let mut context = Context::new();
+        let expr = parse("let first x y = x; first 1 true").expect("valid syntax");
+        let result = expr.infer(&mut context, &Flags::all());
+        assert_eq!(
+            result,
+            Ok(Type::Con("Int")),
+            "application by juxtaposition is left-associative"
+        );
+    }
+
+    #[test]
+    fn test_parse_record_and_field_access() {
+        let mut context = Context::new();
+        let expr = parse("{ a: 1, b: true }.a").expect("valid syntax");
+        let result = expr.infer(&mut context, &Flags::all());
+        assert_eq!(
+            result,
+            Ok(Type::Con("Int")),
+            "parsed record literal and field access"
+        );
+    }
+
+    #[test]
+    fn test_parse_when() {
+        let mut context = Context::new();
+        let expr = parse("when 1 is 0 -> false, _ -> true").expect("valid syntax");
+        let result = expr.infer(&mut context, &Flags::all());
+        assert_eq!(result, Ok(Type::Con("Bool")), "parsed when expression");
+    }
+
+    #[test]
+    fn test_parse_unknown_character_errors() {
+        let result = parse("1 + 1");
+        match result {
+            Err(err) => assert!(!err.is_eof(), "a bad character is not an eof error"),
+            Ok(expr) => panic!("expected a parse error, got {expr:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_unclosed_let_is_eof_error() {
+        let result = parse("let id = x -> x;");
+        match result {
+            Err(err) => assert!(
+                err.is_eof(),
+                "a let missing its body is incomplete, not invalid"
+            ),
+            Ok(expr) => panic!("expected a parse error, got {expr:?}"),
+        }
+    }
+
+    fn int_binop_scheme() -> Scheme<'static> {
+        Scheme::from(Type::Fn(
+            Box::new(Type::Con("Int")),
+            Box::new(Type::Fn(
+                Box::new(Type::Con("Int")),
+                Box::new(Type::Con("Int")),
+            )),
+        ))
+    }
+
+    #[test]
+    fn test_resolver_supplies_builtin() {
+        let mut builtins = MapResolver::new();
+        builtins.insert("add", int_binop_scheme());
+        let mut context = Context::with_resolver(builtins);
+        let e = parse("add 1 2").expect("valid syntax");
+        let result = e.infer(&mut context, &Flags::all());
+        assert_eq!(
+            result,
+            Ok(Type::Con("Int")),
+            "a builtin exposed through the resolver can be called like any other binding"
+        );
+    }
+
+    #[test]
+    fn test_resolver_without_match_is_still_undefined() {
+        let mut context = Context::with_resolver(MapResolver::new());
+        let e = Expr::new(Span::DUMMY, ExprKind::Id("xyz"));
+        let result = e.infer(&mut context, &Flags::all());
+        assert_eq!(
+            result,
+            Err(Error::new(Span::DUMMY, ErrorKind::Undefined)),
+            "an empty resolver still falls through to Undefined"
+        );
+    }
+
+    #[test]
+    fn test_resolver_instantiates_polymorphic_builtin_fresh_per_use() {
+        // identity : a -> a, used twice should not unify the two call sites' vars
+        let a = Type::Var(0);
+        let mut builtins = MapResolver::new();
+        builtins.insert(
+            "identity",
+            Scheme {
+                bounds: vec![0],
+                constraints: Vec::new(),
+                ty: Type::Fn(Box::new(a.clone()), Box::new(a)),
+            },
+        );
+        let mut context = Context::with_resolver(builtins);
+        // identity is applied to an Int and then to a Bool; a shared variable would clash
+        let e = parse("let n = identity 1; identity true").expect("valid syntax");
+        let result = e.infer(&mut context, &Flags::all());
+        assert_eq!(
+            result,
+            Ok(Type::Con("Bool")),
+            "each use of a polymorphic builtin gets its own fresh instantiation"
+        );
+    }
+
+    #[test]
+    fn test_lexical_scope_shadows_resolver() {
+        let mut builtins = MapResolver::new();
+        builtins.insert("x", Scheme::from(Type::Con("Int")));
+        let mut context = Context::with_resolver(builtins);
+        let e = parse("let x = true; x").expect("valid syntax");
+        let result = e.infer(&mut context, &Flags::all());
+        assert_eq!(
+            result,
+            Ok(Type::Con("Bool")),
+            "a lexically bound name shadows a resolver entry of the same name"
+        );
+    }
+
+    fn num_binop_scheme() -> Scheme<'static> {
+        Scheme {
+            bounds: vec![0],
+            constraints: vec![Constraint {
+                class: "Num",
+                ty: Type::Var(0),
+            }],
+            ty: Type::Fn(
+                Box::new(Type::Var(0)),
+                Box::new(Type::Fn(Box::new(Type::Var(0)), Box::new(Type::Var(0)))),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_top_level_constraint_solved_when_instance_exists() {
+        let mut builtins = MapResolver::new();
+        builtins.insert("add", num_binop_scheme());
+        let mut context = Context::with_resolver(builtins);
+        context.add_instance("Num", "Int");
+        let e = parse("add 1 2").expect("valid syntax");
+        let ty = e
+            .infer(&mut context, &Flags::all())
+            .expect("inference succeeds");
+        context
+            .solve_wanted(e.span)
+            .expect("Int has a registered Num instance");
+        assert_eq!(ty, Type::Con("Int"));
+    }
+
+    #[test]
+    fn test_generalized_binding_keeps_constraint_until_applied() {
+        let mut builtins = MapResolver::new();
+        builtins.insert("add", num_binop_scheme());
+        let mut context = Context::with_resolver(builtins);
+        context.add_instance("Num", "Int");
+        // let double = x -> add x x; double 1
+        let e = parse("let double = x -> add x x; double 1").expect("valid syntax");
+        let ty = e
+            .infer(&mut context, &Flags::all())
+            .expect("inference succeeds");
+        context
+            .solve_wanted(e.span)
+            .expect("double's Num obligation is satisfied once applied to an Int");
+        assert_eq!(context.resolve_deep(&ty), Type::Con("Int"));
+    }
+
+    #[test]
+    fn test_no_instance_rejects_unconstrained_application() {
+        let mut builtins = MapResolver::new();
+        builtins.insert("add", num_binop_scheme());
+        let mut context = Context::with_resolver(builtins);
+        context.add_instance("Num", "Int");
+        // let double = x -> add x x; double true
+        let e = parse("let double = x -> add x x; double true").expect("valid syntax");
+        e.infer(&mut context, &Flags::all())
+            .expect("inference succeeds structurally before constraints are checked");
+        let result = context.solve_wanted(e.span);
+        assert_eq!(
+            result,
+            Err(Error::new(
+                e.span,
+                ErrorKind::NoInstance {
+                    class: "Num",
+                    ty: Type::Con("Bool"),
+                },
             )),
-        )),
+            "there is no Num instance for Bool, so double true is rejected"
+        );
+    }
+}
+
+// The builtins handed to every REPL `Context` (`main` registers the matching
+// `Num Int` instance alongside), so a session
+// can actually use `add` (and its `Num` constraint) rather than only the
+// unit tests exercising `SymbolResolver`/`add_instance` in isolation.
+fn prelude() -> MapResolver<'static> {
+    let mut builtins = MapResolver::new();
+    builtins.insert(
+        "add",
+        Scheme {
+            bounds: vec![0],
+            constraints: vec![Constraint {
+                class: "Num",
+                ty: Type::Var(0),
+            }],
+            ty: Type::Fn(
+                Box::new(Type::Var(0)),
+                Box::new(Type::Fn(Box::new(Type::Var(0)), Box::new(Type::Var(0)))),
+            ),
+        },
     );
-    let flags = Flags::all();
-    let result = expr.infer(&mut context, &flags);
-    let _ = dbg!(result);
+    builtins
+}
+
+// A small REPL in the spirit of the Schala meta-interpreter: read an
+// expression, infer its type against a fresh `Context`, and print the
+// result. Reads further lines instead of erroring out while the input is
+// merely incomplete (an unclosed paren, an unfinished `let`), and renders
+// parse/inference failures instead of panicking.
+fn main() {
+    let stdin = io::stdin();
+    let mut buffer = String::new();
+    loop {
+        print!("{} ", if buffer.is_empty() { ">" } else { "|" });
+        io::stdout().flush().ok();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            println!();
+            break;
+        }
+
+        if buffer.is_empty() {
+            match line.trim() {
+                ":quit" | ":q" => break,
+                _ => {}
+            }
+        }
+        buffer.push_str(&line);
+
+        let source = buffer.trim_start().strip_prefix(":type").unwrap_or(&buffer);
+
+        match parse(source) {
+            Ok(expr) => {
+                let mut context = Context::with_resolver(prelude());
+                context.add_instance("Num", "Int");
+                let result = expr
+                    .infer(&mut context, &Flags::all())
+                    .and_then(|ty| context.solve_wanted(expr.span).map(|()| ty));
+                match result {
+                    Ok(ty) => println!("{}", context.resolve_deep(&ty)),
+                    Err(err) => println!("{}", err.render(source)),
+                }
+                drop(context);
+                buffer.clear();
+            }
+            Err(err) if err.is_eof() => {} // keep reading more lines
+            Err(err) => {
+                println!("{}", err.render(source));
+                buffer.clear();
+            }
+        }
+    }
 }